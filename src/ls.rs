@@ -1,7 +1,19 @@
 use lsp_server::{Message, Notification, Request, Response};
-use lsp_types::{Location, Position, Range, Url};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, Diagnostic, DiagnosticSeverity, FoldingRange,
+    FoldingRangeKind, Location, Position, PositionEncodingKind, Range,
+    TextDocumentContentChangeEvent, Url,
+};
+use ropey::Rope;
 use std::collections::{HashMap, LinkedList};
 
+/// Nasal standard-library functions always offered as completions, since
+/// they have no `func`/`var` binding for `Definitions` to find.
+const BUILTIN_FUNCTIONS: &[&str] = &[
+    "print", "size", "setprop", "getprop", "append", "contains", "keys", "substr", "sprintf",
+    "num", "str", "die",
+];
+
 pub trait Verb {
     fn method(&self) -> &str;
     fn method_and(&self) -> (&str, Option<&serde_json::Value>);
@@ -25,18 +37,23 @@ impl Verb for Message {
 #[derive(Clone, Debug)]
 pub struct File {
     uri: String,
-    text: String,
+    text: Rope,
     ast: String,
-    scopes: LinkedList<[u32; 2]>,
+    // Indexed by `scope_idx` (the order `{` was encountered in), not
+    // positionally compacted, so an unmatched brace leaves a `None` hole
+    // instead of shifting every later scope's index.
+    scopes: Vec<Option<[u32; 2]>>,
+    definitions: Definitions,
 }
 impl Default for File {
     #[inline]
     fn default() -> Self {
         File {
             uri: "".into(),
-            text: "".into(),
+            text: Rope::new(),
             ast: "".into(),
-            scopes: LinkedList::new(),
+            scopes: Vec::new(),
+            definitions: Definitions::new(),
         }
     }
 }
@@ -45,14 +62,20 @@ impl File {
     pub fn with_text<S: Into<String>>(text: S) -> Self {
         File {
             uri: "".into(),
-            text: text.into(),
+            text: Rope::from_str(&text.into()),
             ast: "".into(),
-            scopes: LinkedList::new(),
+            scopes: Vec::new(),
+            definitions: Definitions::new(),
         }
     }
+    #[inline]
+    fn scope_at(&self, idx: usize) -> Option<[u32; 2]> {
+        self.scopes.get(idx).copied().flatten()
+    }
 }
 pub struct Library {
     catalog: std::collections::HashMap<String, File>,
+    position_encoding: PositionEncodingKind,
 }
 impl Default for Library {
     #[inline]
@@ -65,24 +88,157 @@ impl Library {
     pub fn new() -> Self {
         Self {
             catalog: Self::new_catalog(),
+            position_encoding: PositionEncodingKind::UTF16,
         }
     }
     #[inline]
     fn new_catalog() -> std::collections::HashMap<String, File> {
         std::collections::HashMap::new()
     }
-    pub fn add_file(&mut self, filepath: String, text: String) -> Result<(), String> {
+    /// Records the position encoding negotiated with the client at
+    /// `initialize` time, so later `Position`<->offset conversions agree
+    /// with how the client counts `character`.
+    #[inline]
+    pub fn set_position_encoding(&mut self, encoding: PositionEncodingKind) {
+        self.position_encoding = encoding;
+    }
+    pub fn add_file(&mut self, filepath: String, text: String) -> Vec<Diagnostic> {
         let mut file = File::default();
         file.uri = filepath.clone();
-        file.text = text.clone().into();
+        file.text = Rope::from_str(&text);
         file.ast = "".into();
-        let res = Self::process_scopes(&mut file);
-        self.catalog.insert(filepath.clone(), file);
-        return res;
+        let diagnostics = Self::process_scopes(&mut file, &self.position_encoding);
+        Self::process_definitions(&mut file, &self.position_encoding);
+        self.catalog.insert(filepath, file);
+        diagnostics
+    }
+    pub fn edit_file(
+        &mut self,
+        filepath: String,
+        changes: Vec<TextDocumentContentChangeEvent>,
+    ) -> Vec<Diagnostic> {
+        let mut file = self.get_file_or_blank(filepath.clone());
+        for change in changes {
+            match change.range {
+                Some(range) => {
+                    let start =
+                        position_to_char_idx(&file.text, range.start, &self.position_encoding);
+                    let end =
+                        position_to_char_idx(&file.text, range.end, &self.position_encoding);
+                    file.text.remove(start..end);
+                    file.text.insert(start, &change.text);
+                }
+                None => file.text = Rope::from_str(&change.text),
+            }
+        }
+        let diagnostics = Self::process_scopes(&mut file, &self.position_encoding);
+        Self::process_definitions(&mut file, &self.position_encoding);
+        self.catalog.insert(filepath, file);
+        diagnostics
     }
     #[inline]
-    fn get_file(&self, filepath: String) -> Option<File> {
-        self.catalog.get(&filepath).cloned()
+    pub fn close_file(&mut self, filepath: String) {
+        self.catalog.remove(&filepath);
+    }
+    /// Resolves `textDocument/definition` for the identifier under `position`
+    /// in the given file, using that file's scope-aware `Definitions` table.
+    pub fn goto_definition(&self, filepath: &str, position: Position) -> Result<Location, String> {
+        let file = self
+            .catalog
+            .get(filepath)
+            .ok_or_else(|| format!("No file open for uri:{}", filepath))?;
+        let word = word_at(&file.text, position, &self.position_encoding)
+            .ok_or_else(|| "No identifier under cursor".to_string())?;
+        let uri = Url::parse(&file.uri).map_err(|err| err.to_string())?;
+        let reference = Location {
+            uri,
+            range: Range {
+                start: position,
+                end: position,
+            },
+        };
+        file.definitions
+            .definition(&word, &reference)
+            .map(|def| def.location().clone())
+    }
+    /// Translates the scopes already computed for `filepath` into editor
+    /// folding ranges, skipping blocks that span a single line.
+    pub fn folding_ranges(&self, filepath: &str) -> Vec<FoldingRange> {
+        let file = match self.catalog.get(filepath) {
+            Some(file) => file,
+            None => return Vec::new(),
+        };
+        file.scopes
+            .iter()
+            .filter_map(|scope| *scope)
+            .filter(|[start, end]| start < end)
+            .map(|[start, end]| FoldingRange {
+                start_line: start,
+                start_character: None,
+                end_line: end,
+                end_character: None,
+                kind: Some(FoldingRangeKind::Region),
+                collapsed_text: None,
+            })
+            .collect()
+    }
+    /// Completion candidates for `position` in `filepath`: every function and
+    /// variable whose enclosing scope contains the cursor line, prefix
+    /// filtered by the partial identifier to its left, preferring the
+    /// innermost binding when a name is shadowed, plus the Nasal builtins.
+    pub fn completions(&self, filepath: &str, position: Position) -> Vec<CompletionItem> {
+        let file = match self.catalog.get(filepath) {
+            Some(file) => file,
+            None => return Vec::new(),
+        };
+        let prefix = word_before(&file.text, position, &self.position_encoding);
+        let mut items: HashMap<String, CompletionItem> = HashMap::new();
+        let mut innermost_size: HashMap<String, u32> = HashMap::new();
+        for (name, defs) in file.definitions.iter() {
+            for def in defs {
+                let in_scope = match def.scope() {
+                    Some([start, end]) => start <= position.line && position.line <= end,
+                    None => true,
+                };
+                if !in_scope {
+                    continue;
+                }
+                let size = match def.scope() {
+                    Some([start, end]) => end - start,
+                    None => u32::MAX,
+                };
+                let shadows_existing = match innermost_size.get(name) {
+                    Some(existing) => size < *existing,
+                    None => true,
+                };
+                if shadows_existing {
+                    innermost_size.insert(name.clone(), size);
+                    let kind = match def {
+                        NasalLspType::FuncDef(..) => CompletionItemKind::FUNCTION,
+                        _ => CompletionItemKind::VARIABLE,
+                    };
+                    items.insert(
+                        name.clone(),
+                        CompletionItem {
+                            label: name.clone(),
+                            kind: Some(kind),
+                            ..Default::default()
+                        },
+                    );
+                }
+            }
+        }
+        for builtin in BUILTIN_FUNCTIONS {
+            items.entry(builtin.to_string()).or_insert_with(|| CompletionItem {
+                label: builtin.to_string(),
+                kind: Some(CompletionItemKind::FUNCTION),
+                ..Default::default()
+            });
+        }
+        items
+            .into_values()
+            .filter(|item| item.label.starts_with(&prefix))
+            .collect()
     }
     #[inline]
     fn get_file_or_blank(&self, filepath: String) -> File {
@@ -92,59 +248,158 @@ impl Library {
         };
         file
     }
-    fn process_scopes(file: &mut File) -> Result<(), String> {
-        let mut failure = None;
+    fn process_scopes(file: &mut File, encoding: &PositionEncodingKind) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
         let mut line_number: u32 = 0;
         let mut scope_idx: usize = 0;
-        //      [linenumber, scope_idx]
+        //      [linenumber, scope_idx, column]
         let mut init_scopes: LinkedList<Initscope> = LinkedList::new();
         //       Option<[start, end]>
         let mut inter_scopes: LinkedList<Option<[u32; 2]>> = LinkedList::new();
 
         file.text.lines().for_each(|line| {
-            if let Some(chars) = line.trim_start().get(..1) {
-                if chars != "#" {
-                    line.chars().for_each(|char| match char {
-                        '{' => {
-                            init_scopes.push_back(Initscope {
-                                start: line_number,
-                                idx: scope_idx,
-                            });
-                            inter_scopes.push_back(None);
-                            scope_idx += 1;
-                        }
-                        '}' => match init_scopes.pop_back() {
-                            Some(scope) => {
-                                let mut back = inter_scopes.split_off(scope.idx);
-                                back.pop_front();
-                                back.push_front(Some([scope.start, line_number]));
-                                inter_scopes.append(&mut back);
+            if let Some(first) = line.chars().find(|ch| !ch.is_whitespace()) {
+                if first != '#' {
+                    let mut column: u32 = 0;
+                    line.chars().for_each(|char| {
+                        match char {
+                            '{' => {
+                                init_scopes.push_back(Initscope {
+                                    start: line_number,
+                                    column,
+                                    idx: scope_idx,
+                                });
+                                inter_scopes.push_back(None);
+                                scope_idx += 1;
                             }
-                            None => failure = Some(Err("Unmatched ".into())),
-                        },
-                        _ => {}
+                            '}' => match init_scopes.pop_back() {
+                                Some(scope) => {
+                                    let mut back = inter_scopes.split_off(scope.idx);
+                                    back.pop_front();
+                                    back.push_front(Some([scope.start, line_number]));
+                                    inter_scopes.append(&mut back);
+                                }
+                                None => diagnostics
+                                    .push(brace_diagnostic(line_number, column, "Unmatched }")),
+                            },
+                            _ => {}
+                        }
+                        column += encoded_char_len(char, encoding);
                     });
                 }
-                line_number += 1;
             }
+            line_number += 1;
         });
-        let final_scopes: LinkedList<[u32; 2]> = inter_scopes
-            .iter()
-            .map(|opt: &Option<[u32; 2]>| match opt {
-                Some(a) => a.clone(),
-                None => {
-                    failure = Some(Err("Scope Parse Failed".into()));
-                    [0, 0]
+        for unmatched in init_scopes {
+            diagnostics.push(brace_diagnostic(unmatched.start, unmatched.column, "Unmatched {"));
+        }
+        file.scopes = inter_scopes.into_iter().collect();
+        diagnostics
+    }
+    /// Scans `file.text` for `func`/`var` bindings and records each as a
+    /// `NasalLspType` tagged with the line range of its enclosing scope
+    /// (looked up from `file.scopes`, already populated by `process_scopes`).
+    fn process_definitions(file: &mut File, encoding: &PositionEncodingKind) {
+        let mut definitions = Definitions::new();
+        let uri = Url::parse(&file.uri).unwrap_or_else(|_| Url::parse("file:///").unwrap());
+        let mut line_number: u32 = 0;
+        let mut scope_idx: usize = 0;
+        let mut scope_stack: LinkedList<usize> = LinkedList::new();
+
+        file.text.lines().for_each(|line| {
+            if let Some(first) = line.chars().find(|ch| !ch.is_whitespace()) {
+                if first != '#' {
+                    let chars: Vec<char> = line.chars().collect();
+                    let mut col = 0usize;
+                    while col < chars.len() {
+                        match chars[col] {
+                            '{' => {
+                                scope_stack.push_back(scope_idx);
+                                scope_idx += 1;
+                                col += 1;
+                            }
+                            '}' => {
+                                scope_stack.pop_back();
+                                col += 1;
+                            }
+                            c if c.is_alphabetic() || c == '_' => {
+                                let start = col;
+                                while col < chars.len()
+                                    && (chars[col].is_alphanumeric() || chars[col] == '_')
+                                {
+                                    col += 1;
+                                }
+                                let word: String = chars[start..col].iter().collect();
+                                if word == "func" || word == "var" {
+                                    let mut ident_start = col;
+                                    while ident_start < chars.len()
+                                        && chars[ident_start].is_whitespace()
+                                    {
+                                        ident_start += 1;
+                                    }
+                                    let mut ident_end = ident_start;
+                                    while ident_end < chars.len()
+                                        && (chars[ident_end].is_alphanumeric()
+                                            || chars[ident_end] == '_')
+                                    {
+                                        ident_end += 1;
+                                    }
+                                    if ident_end > ident_start {
+                                        let name: String =
+                                            chars[ident_start..ident_end].iter().collect();
+                                        let location = Location {
+                                            uri: uri.clone(),
+                                            range: Range {
+                                                start: Position::new(
+                                                    line_number,
+                                                    char_index_to_encoded_column(
+                                                        &chars,
+                                                        ident_start,
+                                                        encoding,
+                                                    ),
+                                                ),
+                                                end: Position::new(
+                                                    line_number,
+                                                    char_index_to_encoded_column(
+                                                        &chars, ident_end, encoding,
+                                                    ),
+                                                ),
+                                            },
+                                        };
+                                        let scope = scope_stack
+                                            .back()
+                                            .and_then(|idx| file.scope_at(*idx));
+                                        let def = if word == "func" {
+                                            NasalLspType::FuncDef(location, scope)
+                                        } else {
+                                            NasalLspType::IdentDef(location, scope)
+                                        };
+                                        definitions.add(&name, def);
+                                        col = ident_end;
+                                    }
+                                }
+                            }
+                            _ => col += 1,
+                        }
+                    }
                 }
-            })
-            .collect();
-        match failure {
-            Some(err) => err,
-            None => {
-                file.scopes = final_scopes;
-                Ok(())
             }
-        }
+            line_number += 1;
+        });
+        file.definitions = definitions;
+    }
+}
+/// Builds an error `Diagnostic` for a single offending brace character.
+#[inline]
+fn brace_diagnostic(line: u32, column: u32, message: &str) -> Diagnostic {
+    Diagnostic {
+        range: Range {
+            start: Position::new(line, column),
+            end: Position::new(line, column + 1),
+        },
+        severity: Some(DiagnosticSeverity::ERROR),
+        message: message.into(),
+        ..Default::default()
     }
 }
 #[test]
@@ -164,109 +419,106 @@ fn scope_test() {
                                }
                                    ",
     );
-    assert_eq!(Library::process_scopes(&mut file).is_ok(), true);
+    assert_eq!(
+        Library::process_scopes(&mut file, &PositionEncodingKind::UTF16).is_empty(),
+        true
+    );
 }
 #[derive(Debug)]
 struct Initscope {
     start: u32,
+    column: u32,
     idx: usize,
 }
+#[derive(Clone, Debug, Default)]
 pub struct Definitions {
     defs: std::collections::HashMap<String, LinkedList<NasalLspType>>,
 }
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub enum NasalLspType {
-    FuncDef(Location),
-    IdentDef(Location),
-    Func(Location),
-    IdentRef(Location),
+    FuncDef(Location, Option<[u32; 2]>),
+    IdentDef(Location, Option<[u32; 2]>),
 }
 impl NasalLspType {
     #[inline(always)]
     pub fn location(&self) -> &Location {
         match self {
-            Self::Func(loc) => loc,
-            Self::IdentRef(loc) => loc,
-            Self::FuncDef(loc) => loc,
-            Self::IdentDef(loc) => loc,
-        }
-    }
-    #[inline]
-    pub fn uri(&self) -> String {
-        self.location().uri.as_str().into()
-    }
-}
-trait Valid {
-    fn is_valid(&self) -> bool;
-    fn not_valid(&self) -> bool;
-}
-impl Valid for Location {
-    #[inline(always)]
-    fn is_valid(&self) -> bool {
-        match (
-            self.range.start.line < self.range.end.line,
-            self.range.start.character < self.range.end.character,
-        ) {
-            (true, true) => true,
-            (_, _) => false,
+            Self::FuncDef(loc, _) => loc,
+            Self::IdentDef(loc, _) => loc,
         }
     }
+    /// The `[start, end]` line range of this definition's enclosing scope, or
+    /// `None` for file/global scope.
     #[inline(always)]
-    fn not_valid(&self) -> bool {
-        match (
-            self.range.start.line < self.range.end.line,
-            self.range.start.character < self.range.end.character,
-        ) {
-            (true, true) => false,
-            (_, _) => true,
+    pub fn scope(&self) -> Option<[u32; 2]> {
+        match self {
+            Self::FuncDef(_, scope) => *scope,
+            Self::IdentDef(_, scope) => *scope,
         }
     }
 }
 impl Definitions {
     #[inline]
-    pub fn new() -> std::collections::HashMap<String, LinkedList<NasalLspType>> {
-        std::collections::HashMap::new()
-    }
-    #[inline]
-    fn new_list(&mut self, key: String) {
-        if self.defs.insert(key, LinkedList::new()).is_some() {
-            panic!()
+    pub fn new() -> Self {
+        Self {
+            defs: std::collections::HashMap::new(),
         }
     }
     #[inline]
-    pub fn add(&mut self, key: &String, def: NasalLspType) {
-        match self.defs.get_mut(key) {
-            Some(list) => {
-                if list_search(&list, def.location()).is_none() {
-                    list.push_front(def);
-                }
-            }
-            None => self.new_list(key.clone()),
+    pub fn add(&mut self, key: &str, def: NasalLspType) {
+        let list = self
+            .defs
+            .entry(key.to_string())
+            .or_insert_with(LinkedList::new);
+        if list_search(list, def.location()).is_none() {
+            list.push_front(def);
         }
     }
     #[inline]
-    pub fn matches(&self, key: &String) -> Option<&LinkedList<NasalLspType>> {
+    pub fn matches(&self, key: &str) -> Option<&LinkedList<NasalLspType>> {
         self.defs.get(key)
     }
-    pub fn definition(&self, key: &String, loc: &Location) -> Result<NasalLspType, String> {
-        if let Some(list) = self.matches(key) {
-            match list.iter().fold(
-                Err("No Values".to_string()),
-                |res: Result<&NasalLspType, String>, node: &NasalLspType| {
-                    let _ = 0;
-                    let _ = match res {
-                        Err(_) => res,
-                        Ok(nasaltype) => res,
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &LinkedList<NasalLspType>)> {
+        self.defs.iter()
+    }
+    /// Resolves `key` to the definition that lexically encloses `reference`:
+    /// among candidates whose scope line range contains `reference`'s line
+    /// and whose own position precedes it, the innermost (smallest range)
+    /// wins; a file/global-scope definition (`scope() == None`) is the
+    /// fallback when nothing encloses the reference.
+    pub fn definition(&self, key: &str, reference: &Location) -> Result<NasalLspType, String> {
+        let list = self
+            .matches(key)
+            .ok_or_else(|| format!("No definition exists for ident:{}", key))?;
+        let ref_line = reference.range.start.line;
+        let precedes_ref = |def: &NasalLspType| {
+            let def_start = def.location().range.start;
+            (def_start.line, def_start.character)
+                <= (reference.range.start.line, reference.range.start.character)
+        };
+        let mut best: Option<(&NasalLspType, u32)> = None;
+        let mut fallback: Option<&NasalLspType> = None;
+        for node in list.iter() {
+            match node.scope() {
+                Some([start, end]) if start <= ref_line && ref_line <= end && precedes_ref(node) => {
+                    let size = end - start;
+                    let better = match best {
+                        None => true,
+                        Some((_, best_size)) => size < best_size,
                     };
-                    Ok(node)
-                },
-            ) {
-                Ok(nasaltyperef) => Ok(nasaltyperef.clone()),
-                Err(a) => Err(a),
+                    if better {
+                        best = Some((node, size));
+                    }
+                }
+                None if precedes_ref(node) && fallback.is_none() => fallback = Some(node),
+                _ => {}
             }
-        } else {
-            Err(format!("No definition exists for ident:{}", key))
         }
+        best.map(|(node, _)| node)
+            .or(fallback)
+            .cloned()
+            .ok_or_else(|| format!("No definition in scope for ident:{}", key))
     }
 }
 #[inline]
@@ -285,3 +537,95 @@ fn list_search(list: &LinkedList<NasalLspType>, loc: &Location) -> Option<Locati
         },
     )
 }
+/// The width `ch` contributes to an encoded `character` offset under
+/// `encoding`: UTF-8 bytes, UTF-16 code units, or 1 for UTF-32.
+#[inline]
+fn encoded_char_len(ch: char, encoding: &PositionEncodingKind) -> u32 {
+    if *encoding == PositionEncodingKind::UTF8 {
+        ch.len_utf8() as u32
+    } else if *encoding == PositionEncodingKind::UTF32 {
+        1
+    } else {
+        ch.len_utf16() as u32
+    }
+}
+/// Converts a char index into `chars` to the encoded `character` value the
+/// client expects under `encoding`, for emitting `Position`s built from char
+/// offsets (diagnostics, definitions) rather than consumed from them.
+#[inline]
+fn char_index_to_encoded_column(
+    chars: &[char],
+    char_index: usize,
+    encoding: &PositionEncodingKind,
+) -> u32 {
+    if *encoding == PositionEncodingKind::UTF32 {
+        return char_index as u32;
+    }
+    chars[..char_index.min(chars.len())]
+        .iter()
+        .map(|ch| encoded_char_len(*ch, encoding))
+        .sum()
+}
+/// Maps an LSP `Position` to a char index into `rope`, honoring the
+/// negotiated `encoding` for how `character` counts into the line: UTF-8
+/// counts bytes, UTF-16 counts UTF-16 code units, UTF-32 counts chars
+/// directly. Clamps `pos.line` to the last line in `rope` so an in-spec but
+/// out-of-range position (e.g. just past the final newline) can't panic.
+#[inline]
+fn position_to_char_idx(rope: &Rope, pos: Position, encoding: &PositionEncodingKind) -> usize {
+    let line_idx = (pos.line as usize).min(rope.len_lines().saturating_sub(1));
+    let line_start = rope.line_to_char(line_idx);
+    let line = rope.line(line_idx);
+    if *encoding == PositionEncodingKind::UTF32 {
+        let char_offset = (pos.character as usize).min(line.len_chars());
+        return line_start + char_offset;
+    }
+    let mut unit_count: u32 = 0;
+    let mut char_offset = 0;
+    for ch in line.chars() {
+        if unit_count >= pos.character {
+            break;
+        }
+        unit_count += if *encoding == PositionEncodingKind::UTF8 {
+            ch.len_utf8() as u32
+        } else {
+            ch.len_utf16() as u32
+        };
+        char_offset += 1;
+    }
+    line_start + char_offset
+}
+/// Returns the identifier touching `position`, if any, by expanding
+/// left/right from the char index it maps to while chars are word
+/// characters (alphanumeric or `_`).
+fn word_at(rope: &Rope, position: Position, encoding: &PositionEncodingKind) -> Option<String> {
+    let is_ident = |ch: char| ch.is_alphanumeric() || ch == '_';
+    let len = rope.len_chars();
+    let idx = position_to_char_idx(rope, position, encoding).min(len);
+    let mut start = idx;
+    while start > 0 && is_ident(rope.char(start - 1)) {
+        start -= 1;
+    }
+    let mut end = idx;
+    while end < len && is_ident(rope.char(end)) {
+        end += 1;
+    }
+    if start == end {
+        None
+    } else {
+        Some(rope.slice(start..end).to_string())
+    }
+}
+/// Returns the partial identifier immediately left of `position`, i.e. the
+/// token being typed, for prefix-filtering completions. Empty if the
+/// preceding char isn't a word character.
+fn word_before(rope: &Rope, position: Position, encoding: &PositionEncodingKind) -> String {
+    let is_ident = |ch: char| ch.is_alphanumeric() || ch == '_';
+    let len = rope.len_chars();
+    let idx = position_to_char_idx(rope, position, encoding).min(len);
+    let mut start = idx;
+    while start > 0 && is_ident(rope.char(start - 1)) {
+        start -= 1;
+    }
+    rope.slice(start..idx).to_string()
+}
@@ -1,15 +1,46 @@
 mod ls;
+use std::collections::HashMap;
 use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
-use lsp_types::notification::{DidOpenTextDocument, Notification};
+use crossbeam_channel::{Receiver, Sender};
+
+use lsp_types::notification::{
+    Cancel, DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Notification,
+    PublishDiagnostics,
+};
 use lsp_types::{
-    request::GotoDefinition, GotoDefinitionResponse, InitializeParams, ServerCapabilities,
+    request::{Completion, FoldingRangeRequest, GotoDefinition},
+    CancelParams, CompletionOptions, CompletionParams, CompletionResponse, Diagnostic,
+    FoldingRangeParams, FoldingRangeProviderCapability, GotoDefinitionParams,
+    GotoDefinitionResponse, InitializeParams, InitializeResult, NumberOrString,
+    PositionEncodingKind, PublishDiagnosticsParams, ServerCapabilities, Url,
 };
 use lsp_types::{
-    DidOpenTextDocumentParams, OneOf, TextDocumentSyncCapability, TextDocumentSyncKind,
+    DidChangeTextDocumentParams, DidCloseTextDocumentParams, DidOpenTextDocumentParams, OneOf,
+    TextDocumentSyncCapability, TextDocumentSyncKind,
 };
 
-use lsp_server::{Connection, ExtractError, Message, Request, RequestId, Response};
+use lsp_server::{Connection, ExtractError, Message, Request, RequestId, Response, ResponseError};
+
+/// LSP error code for a request that was aborted via `$/cancelRequest`.
+const REQUEST_CANCELLED: i32 = -32800;
+/// Number of worker threads handling requests concurrently.
+const WORKER_COUNT: usize = 4;
+
+type CancelFlag = Arc<AtomicBool>;
+/// Requests currently being worked on, keyed by id, alongside the document
+/// uri they concern (so a `DidChange` can cancel stale requests for it) and
+/// the flag workers poll to notice a cancellation.
+type InFlight = Arc<Mutex<HashMap<RequestId, (CancelFlag, Url)>>>;
+
+enum Job {
+    GotoDefinition(RequestId, GotoDefinitionParams),
+    FoldingRange(RequestId, FoldingRangeParams),
+    Completion(RequestId, CompletionParams),
+}
 
 fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
     // Note that  we must have our logging only write out to stderr.
@@ -19,17 +50,43 @@ fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
     // also be implemented to use sockets or HTTP.
     let (connection, io_threads) = Connection::stdio();
 
-    // Run the server and wait for the two threads to end (typically by trigger LSP Exit event).
-    let server_capabilities = serde_json::to_value(&ServerCapabilities {
+    // Negotiate the position encoding before answering `initialize`, since it must be
+    // decided before any Position<->offset conversion (sync, definitions, diagnostics) runs.
+    let (initialize_id, initialize_params) = connection.initialize_start()?;
+    let initialize_params: InitializeParams = serde_json::from_value(initialize_params).unwrap();
+    let position_encoding = negotiate_position_encoding(&initialize_params);
+
+    let server_capabilities = ServerCapabilities {
         definition_provider: Some(OneOf::Left(true)),
-        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        folding_range_provider: Some(FoldingRangeProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions::default()),
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(
+            TextDocumentSyncKind::INCREMENTAL,
+        )),
+        position_encoding: Some(position_encoding.clone()),
         ..Default::default()
-    })
-    .unwrap();
-    let initialization_params = connection.initialize(server_capabilities)?;
-    let mut global_library = ls::Library::new();
+    };
+    let initialize_result = InitializeResult {
+        capabilities: server_capabilities,
+        server_info: None,
+    };
+    connection.initialize_finish(initialize_id, serde_json::to_value(&initialize_result).unwrap())?;
+
+    let mut library = ls::Library::new();
+    library.set_position_encoding(position_encoding);
+    let library = Arc::new(Mutex::new(library));
 
-    main_loop(connection, initialization_params, &mut global_library)?;
+    let in_flight: InFlight = Arc::new(Mutex::new(HashMap::new()));
+    let (job_sender, job_receiver) = crossbeam_channel::unbounded::<Job>();
+    spawn_workers(
+        job_receiver,
+        Arc::clone(&library),
+        Arc::clone(&in_flight),
+        connection.sender.clone(),
+    );
+
+    main_loop(&connection, &library, &in_flight, &job_sender)?;
+    drop(job_sender);
     io_threads.join()?;
 
     // Shut down gracefully.
@@ -37,12 +94,193 @@ fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
     Ok(())
 }
 
+/// Picks the best mutually supported position encoding from the client's
+/// `general.position_encodings`, preferring UTF-8, then UTF-16, then UTF-32.
+/// Defaults to UTF-16 (the spec default) when the client advertises nothing.
+fn negotiate_position_encoding(params: &InitializeParams) -> PositionEncodingKind {
+    let offered = params
+        .capabilities
+        .general
+        .as_ref()
+        .and_then(|general| general.position_encodings.clone())
+        .unwrap_or_default();
+    for preferred in [
+        PositionEncodingKind::UTF8,
+        PositionEncodingKind::UTF16,
+        PositionEncodingKind::UTF32,
+    ] {
+        if offered.contains(&preferred) {
+            return preferred;
+        }
+    }
+    PositionEncodingKind::UTF16
+}
+
+/// Runs `WORKER_COUNT` threads draining `job_receiver`, each resolving
+/// requests against the shared `library` and replying directly over
+/// `sender`. Every job checks `in_flight` for its own cancellation flag
+/// before and after doing the (cheap, but in principle long-running) work.
+fn spawn_workers(
+    job_receiver: Receiver<Job>,
+    library: Arc<Mutex<ls::Library>>,
+    in_flight: InFlight,
+    sender: Sender<Message>,
+) {
+    for _ in 0..WORKER_COUNT {
+        let job_receiver = job_receiver.clone();
+        let library = Arc::clone(&library);
+        let in_flight = Arc::clone(&in_flight);
+        let sender = sender.clone();
+        thread::spawn(move || {
+            for job in job_receiver {
+                match job {
+                    Job::GotoDefinition(id, params) => {
+                        handle_goto_definition(id, params, &library, &in_flight, &sender)
+                    }
+                    Job::FoldingRange(id, params) => {
+                        handle_folding_range(id, params, &library, &in_flight, &sender)
+                    }
+                    Job::Completion(id, params) => {
+                        handle_completion(id, params, &library, &in_flight, &sender)
+                    }
+                }
+            }
+        });
+    }
+}
+
+fn handle_goto_definition(
+    id: RequestId,
+    params: GotoDefinitionParams,
+    library: &Arc<Mutex<ls::Library>>,
+    in_flight: &InFlight,
+    sender: &Sender<Message>,
+) {
+    if is_cancelled(in_flight, &id) {
+        send_cancelled(sender, id, in_flight);
+        return;
+    }
+    let doc_position = params.text_document_position_params;
+    let result = library
+        .lock()
+        .unwrap()
+        .goto_definition(&doc_position.text_document.uri.to_string(), doc_position.position);
+    if is_cancelled(in_flight, &id) {
+        send_cancelled(sender, id, in_flight);
+        return;
+    }
+    let result = match result {
+        Ok(location) => Some(GotoDefinitionResponse::Scalar(location)),
+        Err(err) => {
+            eprintln!("gotoDefinition #{id} failed: {err}");
+            None
+        }
+    };
+    let response = Response {
+        id: id.clone(),
+        result: Some(serde_json::to_value(&result).unwrap()),
+        error: None,
+    };
+    let _ = sender.send(Message::Response(response));
+    in_flight.lock().unwrap().remove(&id);
+}
+
+fn handle_folding_range(
+    id: RequestId,
+    params: FoldingRangeParams,
+    library: &Arc<Mutex<ls::Library>>,
+    in_flight: &InFlight,
+    sender: &Sender<Message>,
+) {
+    if is_cancelled(in_flight, &id) {
+        send_cancelled(sender, id, in_flight);
+        return;
+    }
+    let ranges = library
+        .lock()
+        .unwrap()
+        .folding_ranges(&params.text_document.uri.to_string());
+    if is_cancelled(in_flight, &id) {
+        send_cancelled(sender, id, in_flight);
+        return;
+    }
+    let response = Response {
+        id: id.clone(),
+        result: Some(serde_json::to_value(&Some(ranges)).unwrap()),
+        error: None,
+    };
+    let _ = sender.send(Message::Response(response));
+    in_flight.lock().unwrap().remove(&id);
+}
+
+fn handle_completion(
+    id: RequestId,
+    params: CompletionParams,
+    library: &Arc<Mutex<ls::Library>>,
+    in_flight: &InFlight,
+    sender: &Sender<Message>,
+) {
+    if is_cancelled(in_flight, &id) {
+        send_cancelled(sender, id, in_flight);
+        return;
+    }
+    let doc_position = params.text_document_position;
+    let items = library
+        .lock()
+        .unwrap()
+        .completions(&doc_position.text_document.uri.to_string(), doc_position.position);
+    if is_cancelled(in_flight, &id) {
+        send_cancelled(sender, id, in_flight);
+        return;
+    }
+    let response = Response {
+        id: id.clone(),
+        result: Some(serde_json::to_value(&Some(CompletionResponse::Array(items))).unwrap()),
+        error: None,
+    };
+    let _ = sender.send(Message::Response(response));
+    in_flight.lock().unwrap().remove(&id);
+}
+
+#[inline]
+fn is_cancelled(in_flight: &InFlight, id: &RequestId) -> bool {
+    in_flight
+        .lock()
+        .unwrap()
+        .get(id)
+        .map(|(flag, _)| flag.load(Ordering::SeqCst))
+        .unwrap_or(false)
+}
+
+fn send_cancelled(sender: &Sender<Message>, id: RequestId, in_flight: &InFlight) {
+    let response = Response {
+        id: id.clone(),
+        result: None,
+        error: Some(ResponseError {
+            code: REQUEST_CANCELLED,
+            message: "request cancelled".into(),
+            data: None,
+        }),
+    };
+    let _ = sender.send(Message::Response(response));
+    in_flight.lock().unwrap().remove(&id);
+}
+
+/// Converts a `$/cancelRequest` id (a `NumberOrString`) into the
+/// `lsp_server::RequestId` used to key in-flight requests.
+fn to_request_id(id: NumberOrString) -> RequestId {
+    match id {
+        NumberOrString::Number(n) => RequestId::from(n),
+        NumberOrString::String(s) => RequestId::from(s),
+    }
+}
+
 fn main_loop(
-    connection: Connection,
-    params: serde_json::Value,
-    lib: &mut ls::Library,
+    connection: &Connection,
+    library: &Arc<Mutex<ls::Library>>,
+    in_flight: &InFlight,
+    job_sender: &Sender<Job>,
 ) -> Result<(), Box<dyn Error + Sync + Send>> {
-    let _params: InitializeParams = serde_json::from_value(params).unwrap();
     eprintln!("starting example main loop");
     for msg in &connection.receiver {
         eprintln!("got msg: {msg:?}");
@@ -52,22 +290,37 @@ fn main_loop(
                     return Ok(());
                 }
                 eprintln!("got request: {req:?}");
-                match cast::<GotoDefinition>(req) {
+                let req = match cast::<GotoDefinition>(req) {
                     Ok((id, params)) => {
                         eprintln!("got gotoDefinition request #{id}: {params:?}");
-                        let result = Some(GotoDefinitionResponse::Array(Vec::new()));
-                        let result = serde_json::to_value(&result).unwrap();
-                        let resp = Response {
-                            id,
-                            result: Some(result),
-                            error: None,
-                        };
-                        connection.sender.send(Message::Response(resp))?;
+                        let uri = params.text_document_position_params.text_document.uri.clone();
+                        dispatch(in_flight, job_sender, id, uri, Job::GotoDefinition, params);
+                        continue;
+                    }
+                    Err(err @ ExtractError::JsonError { .. }) => panic!("{err:?}"),
+                    Err(ExtractError::MethodMismatch(req)) => req,
+                };
+                let req = match cast::<FoldingRangeRequest>(req) {
+                    Ok((id, params)) => {
+                        eprintln!("got foldingRange request #{id}: {params:?}");
+                        let uri = params.text_document.uri.clone();
+                        dispatch(in_flight, job_sender, id, uri, Job::FoldingRange, params);
                         continue;
                     }
                     Err(err @ ExtractError::JsonError { .. }) => panic!("{err:?}"),
                     Err(ExtractError::MethodMismatch(req)) => req,
                 };
+                let req = match cast::<Completion>(req) {
+                    Ok((id, params)) => {
+                        eprintln!("got completion request #{id}: {params:?}");
+                        let uri = params.text_document_position.text_document.uri.clone();
+                        dispatch(in_flight, job_sender, id, uri, Job::Completion, params);
+                        continue;
+                    }
+                    Err(err @ ExtractError::JsonError { .. }) => panic!("{err:?}"),
+                    Err(ExtractError::MethodMismatch(req)) => req,
+                };
+                let _ = req;
                 // ...
             }
             Message::Response(resp) => {
@@ -78,10 +331,43 @@ fn main_loop(
                     if let Ok(params) =
                         not.extract::<DidOpenTextDocumentParams>(DidOpenTextDocument::METHOD)
                     {
-                        let _ = lib.add_file(
-                            params.text_document.uri.to_string(),
-                            params.text_document.text,
-                        );
+                        let uri = params.text_document.uri;
+                        let diagnostics = library
+                            .lock()
+                            .unwrap()
+                            .add_file(uri.to_string(), params.text_document.text);
+                        publish_diagnostics(connection, uri, diagnostics)?;
+                    }
+                }
+                DidChangeTextDocument::METHOD => {
+                    if let Ok(params) =
+                        not.extract::<DidChangeTextDocumentParams>(DidChangeTextDocument::METHOD)
+                    {
+                        let uri = params.text_document.uri;
+                        let diagnostics = library
+                            .lock()
+                            .unwrap()
+                            .edit_file(uri.to_string(), params.content_changes);
+                        cancel_stale_requests(in_flight, &uri);
+                        publish_diagnostics(connection, uri, diagnostics)?;
+                    }
+                }
+                DidCloseTextDocument::METHOD => {
+                    if let Ok(params) =
+                        not.extract::<DidCloseTextDocumentParams>(DidCloseTextDocument::METHOD)
+                    {
+                        library
+                            .lock()
+                            .unwrap()
+                            .close_file(params.text_document.uri.to_string());
+                    }
+                }
+                Cancel::METHOD => {
+                    if let Ok(params) = not.extract::<CancelParams>(Cancel::METHOD) {
+                        let id = to_request_id(params.id);
+                        if let Some((flag, _)) = in_flight.lock().unwrap().get(&id) {
+                            flag.store(true, Ordering::SeqCst);
+                        }
                     }
                 }
                 _ => {}
@@ -91,6 +377,50 @@ fn main_loop(
     Ok(())
 }
 
+/// Registers `id` as in-flight for `uri` and enqueues `make_job(id, params)`
+/// for a worker to pick up.
+fn dispatch<P>(
+    in_flight: &InFlight,
+    job_sender: &Sender<Job>,
+    id: RequestId,
+    uri: Url,
+    make_job: impl FnOnce(RequestId, P) -> Job,
+    params: P,
+) {
+    let cancel_flag: CancelFlag = Arc::new(AtomicBool::new(false));
+    in_flight
+        .lock()
+        .unwrap()
+        .insert(id.clone(), (cancel_flag, uri));
+    let _ = job_sender.send(make_job(id, params));
+}
+
+/// Marks every in-flight request for `uri` as cancelled, since a superseding
+/// edit just landed and their results would be stale.
+fn cancel_stale_requests(in_flight: &InFlight, uri: &Url) {
+    for (flag, entry_uri) in in_flight.lock().unwrap().values() {
+        if entry_uri == uri {
+            flag.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+fn publish_diagnostics(
+    connection: &Connection,
+    uri: Url,
+    diagnostics: Vec<Diagnostic>,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let params = PublishDiagnosticsParams {
+        uri,
+        diagnostics,
+        version: None,
+    };
+    let notification =
+        lsp_server::Notification::new(PublishDiagnostics::METHOD.to_string(), params);
+    connection.sender.send(Message::Notification(notification))?;
+    Ok(())
+}
+
 fn cast<R>(req: Request) -> Result<(RequestId, R::Params), ExtractError<Request>>
 where
     R: lsp_types::request::Request,